@@ -1,17 +1,25 @@
 use crate::util::ofile::OutputFile;
+use crate::wasm::compression::Compression;
 
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::fs::File;
+use std::io;
 use std::io::prelude::*;
 use std::io::SeekFrom;
 use std::path::{Path, PathBuf};
 
-use anyhow::Result;
+use anyhow::{bail, Result};
 use codicon::*;
 use lebicon::Leb128;
 use structopt::StructOpt;
 use tar::{Archive, Builder};
-use wasmparser::{Chunk, Parser, Payload};
+use wasmparser::Payload;
+
+/// Largest `--dict-size` (in MiB) liblzma's LZMA2 encoder supports. Kept
+/// well under `u32::MAX / (1024 * 1024)` so converting the flag to bytes
+/// can never overflow.
+const MAX_DICT_SIZE_MIB: u32 = 1536;
 
 #[derive(StructOpt, Debug)]
 pub struct Bundle {
@@ -26,24 +34,192 @@ pub struct Bundle {
 
     #[structopt(short, long, default_value = ".enarx.resources")]
     section: String,
+
+    /// Codec used to compress the embedded resource tarball. A codec other
+    /// than `none` shrinks the output wasm at the cost of extra bundling
+    /// time (and, for `xz`, peak memory proportional to `--dict-size`).
+    #[structopt(long, default_value = "none", possible_values = &["none", "gzip", "zstd", "xz"])]
+    compression: Compression,
+
+    /// Compression level passed to the chosen codec.
+    #[structopt(long, default_value = "6")]
+    compression_level: u32,
+
+    /// LZMA dictionary size, in MiB, used by the `xz` codec. A larger
+    /// window captures more redundancy across many similar files and
+    /// shrinks the archive further, at the cost of higher peak memory
+    /// during compression.
+    #[structopt(long, default_value = "64")]
+    dict_size: u32,
+
+    /// Produce a byte-reproducible tarball: entries are sorted
+    /// lexicographically and their mtime/uid/gid/mode are normalized, so
+    /// identical inputs always produce an identical `.enarx.resources`
+    /// section. A prerequisite for signing and caching bundled modules.
+    #[structopt(long)]
+    deterministic: bool,
+
+    /// mtime, in seconds since the Unix epoch, stamped on every entry in
+    /// `--deterministic` mode.
+    #[structopt(long, default_value = "0")]
+    mtime: u64,
+
+    /// Capture extended attributes (Unix only) in PAX headers, so they can
+    /// be restored by `wasm unbundle`.
+    #[structopt(long)]
+    xattrs: bool,
+
+    /// Merge with any existing `.enarx.resources` section instead of
+    /// replacing it outright. Files from `files` override same-named
+    /// entries already present in `iwasm`.
+    #[structopt(long)]
+    merge: bool,
 }
 
-/// Write a tarball containing all the files under the input directory.
-fn mktar(input: &Path, output: &mut File) -> Result<()> {
-    let mut tar = Builder::new(output);
+/// The ustar format can't represent a path longer than 100 bytes. Anything
+/// longer, or containing non-ASCII bytes, needs a PAX extended header ahead
+/// of the entry to carry the real path faithfully.
+fn needs_pax_path(rel: &Path) -> bool {
+    let rel = rel.as_os_str().to_string_lossy();
+    rel.len() > 100 || !rel.is_ascii()
+}
 
-    for entry in walkdir::WalkDir::new(input) {
-        let entry = entry?;
+/// Raw bytes of `path`, used for the PAX `path=` record. On Unix, a path is
+/// just bytes and may not be valid UTF-8 at all; going through
+/// `to_string_lossy` first would permanently replace those bytes with
+/// U+FFFD, which is exactly what PAX extended headers exist to avoid.
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().to_vec()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
 
-        let path = entry.path();
-        if path == input {
-            continue;
+#[cfg(unix)]
+fn collect_xattrs(path: &Path, fields: &mut Vec<(String, Vec<u8>)>) -> Result<()> {
+    for name in xattr::list(path)? {
+        if let Some(value) = xattr::get(path, &name)? {
+            fields.push((format!("SCHILY.xattr.{}", name.to_string_lossy()), value));
         }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn collect_xattrs(_path: &Path, _fields: &mut Vec<(String, Vec<u8>)>) -> Result<()> {
+    Ok(())
+}
+
+/// Appends a single entry to `tar`, emitting a PAX extended header first
+/// when the path needs one (too long for ustar, non-ASCII) or when xattrs
+/// were captured for it.
+fn append_entry(
+    tar: &mut Builder<&mut File>,
+    path: &Path,
+    rel: &Path,
+    deterministic: bool,
+    mtime: u64,
+    xattrs: bool,
+) -> Result<()> {
+    let metadata = path.symlink_metadata()?;
 
-        let rel = path.strip_prefix(input)?;
+    let mut pax_fields: Vec<(String, Vec<u8>)> = Vec::new();
+    if needs_pax_path(rel) {
+        pax_fields.push(("path".to_string(), path_bytes(rel)));
+    }
+    // `collect_xattrs` uses the non-`l*` xattr calls, which follow symlinks:
+    // running it on a symlink would tag the entry with its *target's*
+    // xattrs (or abort on a dangling target) instead of its own.
+    if xattrs && !metadata.file_type().is_symlink() {
+        collect_xattrs(path, &mut pax_fields)?;
+    }
+    if !pax_fields.is_empty() {
+        tar.append_pax_extensions(pax_fields.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+    }
+
+    // A deterministic bundle always needs an explicit header to normalize
+    // metadata; a non-deterministic one only needs one when a PAX record
+    // precedes it, so the real on-disk mtime/uid/gid/mode are preserved.
+    if deterministic {
+        let mut header = tar::Header::new_gnu();
+        header.set_mtime(mtime);
+        header.set_uid(0);
+        header.set_gid(0);
+        header.set_username("")?;
+        header.set_groupname("")?;
+
+        if metadata.is_dir() {
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_size(0);
+            header.set_mode(0o755);
+            tar.append_data(&mut header, rel, std::io::empty())?;
+        } else if metadata.file_type().is_symlink() {
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_mode(0o777);
+            header.set_link_name(std::fs::read_link(path)?)?;
+            tar.append_data(&mut header, rel, std::io::empty())?;
+        } else {
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(metadata.len());
+            header.set_mode(0o644);
+            let mut file = File::open(path)?;
+            tar.append_data(&mut header, rel, &mut file)?;
+        }
+    } else if !pax_fields.is_empty() {
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata(&metadata);
+
+        if metadata.is_dir() {
+            header.set_entry_type(tar::EntryType::Directory);
+            tar.append_data(&mut header, rel, std::io::empty())?;
+        } else if metadata.file_type().is_symlink() {
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            header.set_link_name(std::fs::read_link(path)?)?;
+            tar.append_data(&mut header, rel, std::io::empty())?;
+        } else {
+            let mut file = File::open(path)?;
+            tar.append_data(&mut header, rel, &mut file)?;
+        }
+    } else {
         tar.append_path_with_name(path, rel)?;
     }
 
+    Ok(())
+}
+
+/// Write a tarball containing all the files under the input directory.
+///
+/// In `deterministic` mode, entries are visited in sorted order and each is
+/// given an explicit header with a fixed mtime, zeroed uid/gid and a
+/// normalized mode (0755 for directories, 0644 for files, 0777 for
+/// symlinks), rather than whatever the real mtime/uid/gid/mode on disk
+/// happen to be.
+fn mktar(input: &Path, output: &mut File, deterministic: bool, mtime: u64, xattrs: bool) -> Result<()> {
+    let mut tar = Builder::new(output);
+
+    let mut paths: Vec<PathBuf> = walkdir::WalkDir::new(input)
+        .into_iter()
+        .map(|entry| Ok(entry?.path().to_path_buf()))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|path| path != input)
+        .collect();
+
+    if deterministic {
+        paths.sort();
+    }
+
+    for path in paths {
+        let rel = path.strip_prefix(input)?.to_path_buf();
+        append_entry(&mut tar, &path, &rel, deterministic, mtime, xattrs)?;
+    }
+
     tar.finish()?;
     Ok(())
 }
@@ -54,10 +230,10 @@ fn mktar(input: &Path, output: &mut File) -> Result<()> {
 /// If `files` points to a tarball, we open it.
 ///
 /// Note that the position of the returned `File` is undefined.
-fn prepare_tarball(ifile: &Path) -> Result<File> {
+fn prepare_tarball(ifile: &Path, deterministic: bool, mtime: u64, xattrs: bool) -> Result<File> {
     Ok(if ifile.metadata()?.is_dir() {
         let mut tar = tempfile::tempfile()?;
-        mktar(ifile, &mut tar)?;
+        mktar(ifile, &mut tar, deterministic, mtime, xattrs)?;
         tar
     } else {
         let tar = File::open(ifile)?;
@@ -67,87 +243,287 @@ fn prepare_tarball(ifile: &Path) -> Result<File> {
 }
 
 /// Copies the wasm from `ifile` to `ofile`, but drops the `section` along the
-/// way. Returns the `OutputFile` to allow for further appending.
-fn strip_section<T, U>(section: &str, ifile: T, ofile: U) -> Result<OutputFile<U>>
+/// way. Returns the `OutputFile` to allow for further appending, plus the
+/// bytes of the dropped section when `capture` is set (used by `--merge` to
+/// fold the existing section's contents into the new one instead of
+/// discarding them).
+fn strip_section<T, U>(section: &str, ifile: T, ofile: U, capture: bool) -> Result<(OutputFile<U>, Option<Vec<u8>>)>
 where
     T: AsRef<Path>,
     U: AsRef<Path>,
 {
-    let mut buffer = Vec::new();
-    let mut parser = Parser::new(0);
-    let mut eofile = false;
-    let mut pstack = Vec::new();
-    let mut inwasm = File::open(ifile)?;
     let mut output = OutputFile::create(ofile)?;
+    let mut captured = None;
 
-    loop {
-        let (consumed, payload) = match parser.parse(&buffer, eofile)? {
-            Chunk::Parsed { consumed, payload } => (consumed, payload),
-            Chunk::NeedMoreData(hint) => {
-                assert!(!eofile);
+    crate::wasm::section::for_each_payload(ifile.as_ref(), |payload, raw| {
+        match payload {
+            Payload::CustomSection { name, data, .. } if name == section => {
+                if capture {
+                    captured = Some(data.to_vec());
+                }
+            }
 
-                let len = buffer.len();
-                buffer.extend((0..hint).map(|_| 0u8));
+            _ => {
+                output.write_all(raw)?;
+            }
+        }
 
-                let n = inwasm.read(&mut buffer[len..])?;
-                buffer.truncate(len + n);
+        Ok(true)
+    })?;
 
-                eofile = n == 0;
-                continue;
-            }
-        };
+    Ok((output, captured))
+}
 
-        match payload {
-            Payload::ModuleCodeSectionEntry { parser: sp, .. } => {
-                pstack.push(parser);
-                parser = sp;
-            }
+/// Copies a single tar entry into `builder`, re-emitting its PAX extended
+/// header (e.g. the `SCHILY.xattr.*` records `--xattrs` writes) ahead of the
+/// data, rather than losing it the way cloning just `entry.header()` would.
+fn copy_entry<R: Read>(builder: &mut Builder<&mut File>, entry: &mut tar::Entry<R>, path: &Path) -> Result<()> {
+    if let Some(extensions) = entry.pax_extensions()? {
+        let fields = extensions
+            .map(|ext| {
+                let ext = ext?;
+                Ok((ext.key()?.to_string(), ext.value_bytes().to_vec()))
+            })
+            .collect::<io::Result<Vec<(String, Vec<u8>)>>>()?;
+        builder.append_pax_extensions(fields.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+    }
 
-            Payload::End => {
-                if let Some(p) = pstack.pop() {
-                    parser = p;
-                } else {
-                    return Ok(output);
-                }
-            }
+    let mut header = entry.header().clone();
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    builder.append_data(&mut header, path, data.as_slice())?;
+    Ok(())
+}
 
-            Payload::CustomSection { name, .. } if name == section => {}
+/// Decodes an existing `.enarx.resources` section (codec tag byte followed
+/// by the compressed tarball) and folds it with `new_tar`, a freshly built
+/// raw tarball. Files present in `new_tar` override same-named entries from
+/// the existing section; everything else from the existing section is kept.
+fn merge_tarballs(mut old_section: Vec<u8>, new_tar: &mut File) -> Result<File> {
+    if old_section.is_empty() {
+        bail!("existing `.enarx.resources` section is empty, nothing to merge");
+    }
 
-            _ => {
-                output.write_all(&buffer[..consumed])?;
+    let tag = old_section.remove(0);
+    let mut old_bytes = Vec::new();
+    Compression::from_tag(tag)?
+        .decode(&mut old_section.as_slice())?
+        .read_to_end(&mut old_bytes)?;
+
+    new_tar.seek(SeekFrom::Start(0))?;
+    let mut new_bytes = Vec::new();
+    new_tar.read_to_end(&mut new_bytes)?;
+
+    let mut new_names = HashSet::new();
+    for entry in Archive::new(new_bytes.as_slice()).entries()? {
+        new_names.insert(entry?.path()?.into_owned());
+    }
+
+    let mut merged = tempfile::tempfile()?;
+    {
+        let mut builder = Builder::new(&mut merged);
+
+        for entry in Archive::new(old_bytes.as_slice()).entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            if new_names.contains(&path) {
+                continue;
             }
+
+            copy_entry(&mut builder, &mut entry, &path)?;
         }
 
-        buffer.drain(..consumed);
+        for entry in Archive::new(new_bytes.as_slice()).entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            copy_entry(&mut builder, &mut entry, &path)?;
+        }
+
+        builder.finish()?;
     }
+
+    merged.seek(SeekFrom::Start(0))?;
+    Ok(merged)
 }
 
 impl crate::Command for Bundle {
     fn execute(self) -> anyhow::Result<()> {
+        if self.dict_size == 0 || self.dict_size > MAX_DICT_SIZE_MIB {
+            bail!(
+                "--dict-size must be between 1 and {} MiB, got {}",
+                MAX_DICT_SIZE_MIB,
+                self.dict_size
+            );
+        }
+
         // Encode the name length.
         let name = self.section.as_bytes();
         let mut name_len = Vec::new();
         name.len().encode(&mut name_len, Leb128)?;
 
-        // Get the tarball and its size.
-        let mut tarball = prepare_tarball(&self.files)?;
+        // Get the tarball, merge it with the existing section if requested,
+        // then compress it. The `payload_len` computation has to happen
+        // after compression, since the LEB128 section length must reflect
+        // the compressed size, not the raw tarball size.
+        let mut tarball = prepare_tarball(&self.files, self.deterministic, self.mtime, self.xattrs)?;
+
+        // Strip the section from the existing wasm file, capturing its
+        // bytes if we're going to merge them into the new one.
+        let (mut output, captured) = strip_section(&self.section, &self.iwasm, &self.owasm, self.merge)?;
+
+        if let Some(old_section) = captured {
+            tarball = merge_tarballs(old_section, &mut tarball)?;
+        } else if self.merge {
+            eprintln!(
+                "warning: --merge given but `{}` has no existing `{}` section; nothing to merge",
+                self.iwasm.display(),
+                self.section
+            );
+        }
+
+        let mut tarball =
+            self.compression
+                .encode(&mut tarball, self.compression_level, self.dict_size * 1024 * 1024)?;
         let tarball_len = tarball.seek(SeekFrom::End(0))?;
         tarball.seek(SeekFrom::Start(0))?;
 
-        // Calculate the length of the custom section payload.
-        let payload_len = usize::try_from(tarball_len)? + name.len() + name_len.len();
-
-        // Strip the section from the existing wasm file.
-        let mut output = strip_section(&self.section, self.iwasm, self.owasm)?;
+        // Calculate the length of the custom section payload: the codec tag
+        // byte, the name (with its LEB128 length prefix) and the tarball.
+        let payload_len = usize::try_from(tarball_len)? + 1 + name.len() + name_len.len();
 
         // Write out the custom section.
         output.write_all(&[0])?; // section id == 0 (custom)
         payload_len.encode(&mut output, Leb128)?;
         output.write_all(&name_len)?;
         output.write_all(name)?;
+        output.write_all(&[self.compression.tag()])?;
         std::io::copy(&mut tarball, &mut output)?;
 
         output.done();
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn deterministic_mktar_preserves_symlinks() {
+        let pid = std::process::id();
+        let dir = std::path::PathBuf::from(format!("/tmp/bundle-test.{}", pid));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        std::fs::write(dir.join("real.txt"), b"hello").unwrap();
+        std::os::unix::fs::symlink("real.txt", dir.join("link.txt")).unwrap();
+
+        let mut tar_file = tempfile::tempfile().unwrap();
+        mktar(&dir, &mut tar_file, true, 0, false).unwrap();
+        tar_file.seek(SeekFrom::Start(0)).unwrap();
+
+        let mut archive = Archive::new(&tar_file);
+        let mut saw_symlink = false;
+        for entry in archive.entries().unwrap() {
+            let entry = entry.unwrap();
+            if entry.header().entry_type() == tar::EntryType::Symlink {
+                saw_symlink = true;
+                assert_eq!(entry.link_name().unwrap().unwrap(), Path::new("real.txt"));
+            }
+        }
+        assert!(saw_symlink, "symlink entry was not preserved");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn merge_preserves_pax_extensions() {
+        // An "old" tarball with one file carrying a PAX xattr record.
+        let mut old_tar = tempfile::tempfile().unwrap();
+        {
+            let mut builder = Builder::new(&mut old_tar);
+            builder
+                .append_pax_extensions(vec![("SCHILY.xattr.user.foo", b"bar".as_slice())])
+                .unwrap();
+            let mut header = tar::Header::new_gnu();
+            header.set_size(5);
+            header.set_entry_type(tar::EntryType::Regular);
+            builder.append_data(&mut header, "old.txt", b"hello".as_slice()).unwrap();
+            builder.finish().unwrap();
+        }
+        old_tar.seek(SeekFrom::Start(0)).unwrap();
+        let mut old_tar_bytes = Vec::new();
+        old_tar.read_to_end(&mut old_tar_bytes).unwrap();
+
+        let mut old_section = vec![Compression::None.tag()];
+        old_section.extend_from_slice(&old_tar_bytes);
+
+        // An empty "new" tarball: nothing overrides `old.txt`.
+        let mut new_tar = tempfile::tempfile().unwrap();
+        Builder::new(&mut new_tar).finish().unwrap();
+
+        let mut merged = merge_tarballs(old_section, &mut new_tar).unwrap();
+        let mut merged_bytes = Vec::new();
+        merged.read_to_end(&mut merged_bytes).unwrap();
+
+        let mut archive = Archive::new(merged_bytes.as_slice());
+        let mut entry = archive.entries().unwrap().next().unwrap().unwrap();
+        let extensions: Vec<_> = entry.pax_extensions().unwrap().unwrap().map(|e| e.unwrap()).collect();
+        assert!(extensions.iter().any(|e| e.key().unwrap() == "SCHILY.xattr.user.foo"));
+    }
+
+    #[test]
+    fn merge_new_entry_overrides_old_same_path() {
+        // An "old" tarball with one file.
+        let mut old_tar = tempfile::tempfile().unwrap();
+        {
+            let mut builder = Builder::new(&mut old_tar);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(3);
+            header.set_entry_type(tar::EntryType::Regular);
+            builder.append_data(&mut header, "shared.txt", b"old".as_slice()).unwrap();
+            builder.finish().unwrap();
+        }
+        old_tar.seek(SeekFrom::Start(0)).unwrap();
+        let mut old_tar_bytes = Vec::new();
+        old_tar.read_to_end(&mut old_tar_bytes).unwrap();
+
+        let mut old_section = vec![Compression::None.tag()];
+        old_section.extend_from_slice(&old_tar_bytes);
+
+        // A "new" tarball with an entry at the same path and different content.
+        let mut new_tar = tempfile::tempfile().unwrap();
+        {
+            let mut builder = Builder::new(&mut new_tar);
+            let mut header = tar::Header::new_gnu();
+            header.set_size(3);
+            header.set_entry_type(tar::EntryType::Regular);
+            builder.append_data(&mut header, "shared.txt", b"new".as_slice()).unwrap();
+            builder.finish().unwrap();
+        }
+
+        let mut merged = merge_tarballs(old_section, &mut new_tar).unwrap();
+        let mut merged_bytes = Vec::new();
+        merged.read_to_end(&mut merged_bytes).unwrap();
+
+        let mut archive = Archive::new(merged_bytes.as_slice());
+        let mut entries = archive.entries().unwrap();
+        let mut entry = entries.next().unwrap().unwrap();
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content).unwrap();
+        assert_eq!(content, b"new");
+        assert!(entries.next().is_none(), "old entry at the same path should not survive the merge");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn path_bytes_preserves_non_utf8_bytes() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let raw = &[b'a', 0xff, b'b'][..];
+        let path = Path::new(OsStr::from_bytes(raw));
+
+        assert_eq!(path_bytes(path), raw);
+    }
+}
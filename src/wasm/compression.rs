@@ -0,0 +1,153 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use anyhow::{bail, Result};
+
+/// Compression codec for the tarball stored in the `.enarx.resources`
+/// custom section.
+///
+/// The chosen codec is written as a single byte immediately after the
+/// LEB128-encoded section name, so `wasm unbundle` can recover the right
+/// decoder without any additional metadata.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    const TAG_NONE: u8 = 0;
+    const TAG_GZIP: u8 = 1;
+    const TAG_ZSTD: u8 = 2;
+    const TAG_XZ: u8 = 3;
+
+    /// The one-byte codec tag written into the section payload.
+    pub fn tag(self) -> u8 {
+        match self {
+            Self::None => Self::TAG_NONE,
+            Self::Gzip => Self::TAG_GZIP,
+            Self::Zstd => Self::TAG_ZSTD,
+            Self::Xz => Self::TAG_XZ,
+        }
+    }
+
+    pub fn from_tag(tag: u8) -> Result<Self> {
+        Ok(match tag {
+            Self::TAG_NONE => Self::None,
+            Self::TAG_GZIP => Self::Gzip,
+            Self::TAG_ZSTD => Self::Zstd,
+            Self::TAG_XZ => Self::Xz,
+            _ => bail!("unrecognized compression codec tag: {}", tag),
+        })
+    }
+
+    /// Compresses `input` into a freshly created temporary file and returns
+    /// it rewound to the start.
+    ///
+    /// We compress to a temp file rather than streaming straight into the
+    /// output wasm because the LEB128 section length has to reflect the
+    /// *compressed* size, and the only reliable way to know that size ahead
+    /// of time is to finish writing it somewhere seekable first.
+    pub fn encode(self, input: &mut File, level: u32, dict_size: u32) -> Result<File> {
+        input.seek(SeekFrom::Start(0))?;
+        let mut output = tempfile::tempfile()?;
+
+        match self {
+            Self::None => {
+                io::copy(input, &mut output)?;
+            }
+            Self::Gzip => {
+                let mut enc = flate2::write::GzEncoder::new(&output, flate2::Compression::new(level));
+                io::copy(input, &mut enc)?;
+                enc.finish()?;
+            }
+            Self::Zstd => {
+                let mut enc = zstd::stream::Encoder::new(&output, level as i32)?;
+                io::copy(input, &mut enc)?;
+                enc.finish()?;
+            }
+            Self::Xz => {
+                let mut filters = xz2::stream::Filters::new();
+                let mut opts = xz2::stream::LzmaOptions::new_preset(level)?;
+                opts.dict_size(dict_size);
+                filters.lzma2(&opts);
+                let stream = xz2::stream::Stream::new_stream(xz2::stream::Check::Crc64, &filters)?;
+                let mut enc = xz2::write::XzEncoder::new_stream(&output, stream);
+                io::copy(input, &mut enc)?;
+                enc.finish()?;
+            }
+        }
+
+        output.seek(SeekFrom::Start(0))?;
+        Ok(output)
+    }
+
+    /// Wraps `input` in the decoder matching this codec.
+    pub fn decode<'a>(self, input: &'a mut (dyn Read + 'a)) -> Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::None => Box::new(input),
+            Self::Gzip => Box::new(flate2::read::GzDecoder::new(input)),
+            Self::Zstd => Box::new(zstd::stream::Decoder::new(input)?),
+            Self::Xz => Box::new(xz2::read::XzDecoder::new(input)),
+        })
+    }
+}
+
+impl std::str::FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "none" => Self::None,
+            "gzip" => Self::Gzip,
+            "zstd" => Self::Zstd,
+            "xz" => Self::Xz,
+            _ => bail!("unrecognized compression codec: {}", s),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn roundtrip(codec: Compression) {
+        let mut input = tempfile::tempfile().unwrap();
+        input.write_all(b"hello, enarx").unwrap();
+
+        // The cursor of `input` is left at EOF after writing, same as `mktar`
+        // leaves it; `encode` must rewind it and compress the whole file.
+        let mut encoded = codec.encode(&mut input, 6, 64 * 1024 * 1024).unwrap();
+
+        let mut raw = Vec::new();
+        encoded.read_to_end(&mut raw).unwrap();
+
+        let mut decoded = Vec::new();
+        codec.decode(&mut raw.as_slice()).unwrap().read_to_end(&mut decoded).unwrap();
+
+        assert_eq!(decoded, b"hello, enarx");
+    }
+
+    #[test]
+    fn roundtrip_none() {
+        roundtrip(Compression::None);
+    }
+
+    #[test]
+    fn roundtrip_gzip() {
+        roundtrip(Compression::Gzip);
+    }
+
+    #[test]
+    fn roundtrip_zstd() {
+        roundtrip(Compression::Zstd);
+    }
+
+    #[test]
+    fn roundtrip_xz() {
+        roundtrip(Compression::Xz);
+    }
+}
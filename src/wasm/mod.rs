@@ -1,4 +1,7 @@
 mod bundle;
+mod compression;
+mod section;
+mod unbundle;
 
 use structopt::StructOpt;
 
@@ -6,12 +9,16 @@ use structopt::StructOpt;
 pub enum Wasm {
     /// Embed file resources
     Bundle(bundle::Bundle),
+
+    /// Extract embedded resources
+    Unbundle(unbundle::Unbundle),
 }
 
 impl crate::Command for Wasm {
     fn execute(self) -> anyhow::Result<()> {
         match self {
             Self::Bundle(b) => b.execute(),
+            Self::Unbundle(u) => u.execute(),
         }
     }
 }
@@ -0,0 +1,69 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use anyhow::Result;
+use wasmparser::{Chunk, Parser, Payload};
+
+/// Drives the `wasmparser` `Parser`/`Chunk` state machine over `ifile`,
+/// invoking `on_payload` for every top-level payload with its raw
+/// (unparsed) bytes. Nested code sections are transparently recursed into.
+///
+/// `on_payload` returns whether to keep driving the parser; returning
+/// `Ok(false)` stops early once a caller has found what it was looking for,
+/// without having to read the rest of the module.
+///
+/// This is the shared core of `bundle::strip_section` (which rewrites every
+/// payload except a matched custom section) and `unbundle::read_section`
+/// (which only wants that one section's bytes).
+pub fn for_each_payload<F>(ifile: &Path, mut on_payload: F) -> Result<()>
+where
+    F: FnMut(Payload, &[u8]) -> Result<bool>,
+{
+    let mut buffer = Vec::new();
+    let mut parser = Parser::new(0);
+    let mut eofile = false;
+    let mut pstack = Vec::new();
+    let mut inwasm = File::open(ifile)?;
+
+    loop {
+        let (consumed, payload) = match parser.parse(&buffer, eofile)? {
+            Chunk::Parsed { consumed, payload } => (consumed, payload),
+            Chunk::NeedMoreData(hint) => {
+                assert!(!eofile);
+
+                let len = buffer.len();
+                buffer.extend((0..hint).map(|_| 0u8));
+
+                let n = inwasm.read(&mut buffer[len..])?;
+                buffer.truncate(len + n);
+
+                eofile = n == 0;
+                continue;
+            }
+        };
+
+        match payload {
+            Payload::ModuleCodeSectionEntry { parser: sp, .. } => {
+                pstack.push(parser);
+                parser = sp;
+            }
+
+            Payload::End => {
+                if let Some(p) = pstack.pop() {
+                    parser = p;
+                } else {
+                    return Ok(());
+                }
+            }
+
+            _ => {
+                if !on_payload(payload, &buffer[..consumed])? {
+                    return Ok(());
+                }
+            }
+        }
+
+        buffer.drain(..consumed);
+    }
+}
@@ -0,0 +1,231 @@
+use crate::wasm::compression::Compression;
+use crate::wasm::section::for_each_payload;
+
+use std::io::prelude::*;
+use std::path::{Component, Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use structopt::StructOpt;
+use tar::Archive;
+use wasmparser::Payload;
+
+#[derive(StructOpt, Debug)]
+pub struct Unbundle {
+    /// The input wasm binary to extract resources from
+    iwasm: PathBuf,
+
+    /// The directory to extract files into (not required with `--list`)
+    odir: Option<PathBuf>,
+
+    #[structopt(short, long, default_value = ".enarx.resources")]
+    section: String,
+
+    /// Print entry paths, sizes and modes without writing any files
+    #[structopt(short, long)]
+    list: bool,
+}
+
+/// Drives the shared `for_each_payload` parser loop (also used by
+/// `bundle::strip_section`) and returns the raw payload of the first custom
+/// section named `section`.
+fn read_section(section: &str, ifile: &Path) -> Result<Vec<u8>> {
+    let mut found = None;
+
+    for_each_payload(ifile, |payload, _raw| {
+        if let Payload::CustomSection { name, data, .. } = payload {
+            if found.is_none() && name == section {
+                found = Some(data.to_vec());
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    })?;
+
+    found.ok_or_else(|| anyhow!("no `{}` custom section found in `{}`", section, ifile.display()))
+}
+
+/// Rejects any path component that could escape the extraction directory:
+/// parent directory references, absolute roots and Windows path prefixes.
+fn sanitize_path(path: &Path) -> Result<PathBuf> {
+    let mut out = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => out.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                bail!("refusing to extract entry with a `..` component: {}", path.display())
+            }
+            Component::RootDir | Component::Prefix(_) => {
+                bail!("refusing to extract entry with an absolute path: {}", path.display())
+            }
+        }
+    }
+
+    Ok(out)
+}
+
+/// Checks that a symlink's target can't walk above the extraction root, the
+/// same depth-counting check the `tar` crate itself uses when unpacking.
+fn is_safe_symlink(entry_path: &Path, link: &Path) -> bool {
+    let mut depth = entry_path.parent().map_or(0i32, |p| p.components().count() as i32);
+
+    for component in link.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir => {
+                depth -= 1;
+                if depth < 0 {
+                    return false;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return false,
+        }
+    }
+
+    true
+}
+
+impl crate::Command for Unbundle {
+    fn execute(self) -> anyhow::Result<()> {
+        let mut data = read_section(&self.section, &self.iwasm)?;
+        if data.is_empty() {
+            bail!("`{}` custom section is empty", self.section);
+        }
+
+        let tag = data.remove(0);
+        let compression = Compression::from_tag(tag)?;
+        let mut decoded = compression.decode(&mut data.as_slice())?;
+
+        let mut tar = Vec::new();
+        decoded.read_to_end(&mut tar)?;
+        drop(decoded);
+
+        let mut archive = Archive::new(tar.as_slice());
+
+        if self.list {
+            for entry in archive.entries()? {
+                let entry = entry?;
+                let path = sanitize_path(&entry.path()?)?;
+                println!(
+                    "{}\t{}\t{:o}",
+                    path.display(),
+                    entry.size(),
+                    entry.header().mode()?
+                );
+            }
+
+            return Ok(());
+        }
+
+        let odir = self
+            .odir
+            .ok_or_else(|| anyhow!("an output directory is required unless `--list` is given"))?;
+        std::fs::create_dir_all(&odir)?;
+
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let rel = entry.path()?.into_owned();
+            let safe_rel = sanitize_path(&rel)?;
+            let dest = odir.join(&safe_rel);
+
+            if let Some(parent) = dest.parent() {
+                std::fs::create_dir_all(parent)?;
+            }
+
+            let entry_type = entry.header().entry_type();
+
+            if entry_type.is_hard_link() {
+                // A hardlink's target is just as attacker-controlled as a
+                // symlink's, but resolves relative to the archive root
+                // rather than the entry's own directory, so `is_safe_symlink`
+                // doesn't directly apply. Simplest safe answer: don't follow
+                // them.
+                bail!("refusing to extract hardlink entry `{}`: hardlinks are not supported", rel.display());
+            }
+
+            if entry_type.is_symlink() {
+                let link = entry
+                    .link_name()?
+                    .ok_or_else(|| anyhow!("symlink entry `{}` has no target", rel.display()))?;
+
+                if !is_safe_symlink(&safe_rel, &link) {
+                    bail!(
+                        "refusing to extract symlink `{}` whose target `{}` escapes the output directory",
+                        rel.display(),
+                        link.display()
+                    );
+                }
+
+                #[cfg(unix)]
+                std::os::unix::fs::symlink(&link, &dest)?;
+
+                #[cfg(not(unix))]
+                bail!("symlink entries are not supported on this platform");
+
+                continue;
+            }
+
+            let mut xattrs = Vec::new();
+            if let Some(extensions) = entry.pax_extensions()? {
+                for extension in extensions {
+                    let extension = extension?;
+                    if let Some(name) = extension.key()?.strip_prefix("SCHILY.xattr.") {
+                        xattrs.push((name.to_string(), extension.value_bytes().to_vec()));
+                    }
+                }
+            }
+
+            entry.unpack(&dest)?;
+            restore_xattrs(&dest, &xattrs)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Restores any `SCHILY.xattr.*` PAX records captured by `bundle --xattrs`.
+#[cfg(unix)]
+fn restore_xattrs(dest: &Path, xattrs: &[(String, Vec<u8>)]) -> Result<()> {
+    for (name, value) in xattrs {
+        xattr::set(dest, name, value)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restore_xattrs(_dest: &Path, _xattrs: &[(String, Vec<u8>)]) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_path_rejects_parent_dir() {
+        assert!(sanitize_path(Path::new("../../etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn sanitize_path_rejects_absolute() {
+        assert!(sanitize_path(Path::new("/etc/passwd")).is_err());
+    }
+
+    #[test]
+    fn sanitize_path_keeps_normal_components() {
+        assert_eq!(sanitize_path(Path::new("a/./b")).unwrap(), Path::new("a/b"));
+    }
+
+    #[test]
+    fn is_safe_symlink_rejects_escape() {
+        assert!(!is_safe_symlink(Path::new("a.txt"), Path::new("../../etc/passwd")));
+    }
+
+    #[test]
+    fn is_safe_symlink_allows_sibling() {
+        assert!(is_safe_symlink(Path::new("sub/a.txt"), Path::new("../b.txt")));
+    }
+}